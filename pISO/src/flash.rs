@@ -0,0 +1,114 @@
+use error::{ErrorKind, Result, ResultExt};
+use sha256::Sha256;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+const BLOCK_SIZE: usize = 4 * 1024 * 1024;
+
+// One target device to flash `source` onto.
+pub struct FlashTarget {
+    pub name: String,
+    pub path: PathBuf,
+}
+
+// Shared between the caller and a flash worker thread: `written` lets the
+// caller poll per-target byte progress for the display, `cancel` lets it
+// abort the write mid-stream, and `done` lets the caller tell when the
+// worker thread has finished without blocking on its JoinHandle.
+pub struct FlashProgress {
+    pub written: AtomicUsize,
+    pub cancel: AtomicBool,
+    pub done: AtomicBool,
+}
+
+impl FlashProgress {
+    pub fn new() -> Arc<FlashProgress> {
+        Arc::new(FlashProgress {
+            written: AtomicUsize::new(0),
+            cancel: AtomicBool::new(false),
+            done: AtomicBool::new(false),
+        })
+    }
+}
+
+// Streams `source` onto `target` in fixed-size blocks, fsyncs, then re-reads
+// the written region and compares it against a running hash of the source to
+// catch silent write corruption.
+fn flash_one(source: &Path, target: &FlashTarget, progress: &FlashProgress) -> Result<()> {
+    let mut src = File::open(source).chain_err(|| "failed to open flash source")?;
+    let mut dst = OpenOptions::new()
+        .write(true)
+        .open(&target.path)
+        .chain_err(|| format!("failed to open flash target {}", target.path.display()))?;
+
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; BLOCK_SIZE];
+    let mut total_written = 0usize;
+    loop {
+        if progress.cancel.load(Ordering::SeqCst) {
+            return Err(ErrorKind::Msg(format!("flash to {} cancelled", target.name)).into());
+        }
+        let n = src.read(&mut buf)
+            .chain_err(|| "failed to read flash source")?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        dst.write_all(&buf[..n])
+            .chain_err(|| format!("failed to write to flash target {}", target.name))?;
+        total_written += n;
+        progress.written.store(total_written, Ordering::SeqCst);
+    }
+    dst.sync_all()
+        .chain_err(|| format!("failed to fsync flash target {}", target.name))?;
+    drop(dst);
+
+    let expected = hasher.finalize();
+    let mut verify = File::open(&target.path)
+        .chain_err(|| "failed to reopen flash target for verification")?;
+    let mut verify_hasher = Sha256::new();
+    let mut remaining = total_written;
+    while remaining > 0 {
+        let to_read = remaining.min(BLOCK_SIZE);
+        let n = verify
+            .read(&mut buf[..to_read])
+            .chain_err(|| "failed to read back flash target for verification")?;
+        if n == 0 {
+            break;
+        }
+        verify_hasher.update(&buf[..n]);
+        remaining -= n;
+    }
+    if verify_hasher.finalize() != expected {
+        return Err(ErrorKind::Msg(format!("verification failed for {}", target.name)).into());
+    }
+    Ok(())
+}
+
+// Spawns one worker thread per target so several drives can be flashed from
+// the same source concurrently. Each returned handle's `FlashProgress` can be
+// polled for byte progress and used to cancel that target independently.
+pub fn flash_image(
+    source: PathBuf,
+    targets: Vec<FlashTarget>,
+) -> Vec<(String, Arc<FlashProgress>, thread::JoinHandle<Result<()>>)> {
+    targets
+        .into_iter()
+        .map(|target| {
+            let name = target.name.clone();
+            let progress = FlashProgress::new();
+            let thread_progress = progress.clone();
+            let thread_source = source.clone();
+            let join = thread::spawn(move || {
+                let result = flash_one(&thread_source, &target, &thread_progress);
+                thread_progress.done.store(true, Ordering::SeqCst);
+                result
+            });
+            (name, progress, join)
+        })
+        .collect()
+}
@@ -0,0 +1,134 @@
+use error::{ErrorKind, Result, ResultExt};
+use fsdetect;
+use std::fs;
+use std::path::{Path, PathBuf};
+use udevutils;
+use utils;
+
+const ISO_SUBVOLUME: &str = "ISOS";
+const FORMAT_MOUNT_ROOT: &str = "/tmp/piso-format";
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FsChoice {
+    Vfat,
+    Exfat,
+    Ntfs,
+    Ext4,
+    Btrfs,
+}
+
+impl FsChoice {
+    fn mkfs_cmd(&self) -> &'static str {
+        match *self {
+            FsChoice::Vfat => "mkfs.vfat",
+            FsChoice::Exfat => "mkfs.exfat",
+            FsChoice::Ntfs => "mkfs.ntfs",
+            FsChoice::Ext4 => "mkfs.ext4",
+            FsChoice::Btrfs => "mkfs.btrfs",
+        }
+    }
+
+    // Each mkfs flavour spells "format over whatever's already there without
+    // prompting" differently; mkfs.vfat doesn't have such a flag at all, so
+    // -F there instead pins the FAT size to 32 (otherwise small partitions
+    // silently get formatted as FAT16).
+    fn mkfs_args(&self) -> &'static [&'static str] {
+        match *self {
+            FsChoice::Vfat => &["-F", "32"],
+            FsChoice::Exfat => &["-f"],
+            FsChoice::Ntfs => &["-F"],
+            FsChoice::Ext4 => &["-F"],
+            FsChoice::Btrfs => &["-f"],
+        }
+    }
+
+    // Lets mount_internal fall back on the fs we formatted this volume with
+    // when udev hasn't reported an ID_FS_TYPE yet (e.g. right after a format,
+    // before udev has re-probed the partition), rather than failing to mount.
+    // None for Btrfs: fsdetect has no signature/mounter for it yet.
+    pub fn as_fstype(&self) -> Option<fsdetect::FsType> {
+        match *self {
+            FsChoice::Vfat => Some(fsdetect::FsType::Fat32),
+            FsChoice::Exfat => Some(fsdetect::FsType::Exfat),
+            FsChoice::Ntfs => Some(fsdetect::FsType::Ntfs),
+            FsChoice::Ext4 => Some(fsdetect::FsType::Ext),
+            FsChoice::Btrfs => None,
+        }
+    }
+}
+
+// Lays a GPT partition table down on `device` with a single partition
+// spanning the disk, then formats that partition as `fs`. The partition is
+// reached the same way mount_internal finds one: losetup -fP the backing
+// device and enumerate the resulting partition via udev.
+pub fn format_volume<P: AsRef<Path>>(device: P, fs: FsChoice) -> Result<()> {
+    let device = device.as_ref();
+    utils::run_check_output(
+        "parted",
+        &[
+            "--script",
+            &device.to_string_lossy(),
+            "mklabel",
+            "gpt",
+            "mkpart",
+            "primary",
+            "0%",
+            "100%",
+        ],
+    ).chain_err(|| "failed to write partition table")?;
+
+    let loopback_path =
+        PathBuf::from(utils::run_check_output("losetup", &["-f"])?.trim_right());
+    utils::run_check_output("losetup", &["-fP", &device.to_string_lossy()])?;
+    let loopback_name: String = loopback_path
+        .file_name()
+        .ok_or(ErrorKind::Msg("loopback path has no file name".into()))?
+        .to_string_lossy()
+        .into();
+
+    let result = format_loopback_partition(&loopback_name, fs);
+
+    utils::run_check_output("losetup", &["-d", &loopback_path.to_string_lossy()])?;
+    result
+}
+
+fn format_loopback_partition(loopback_name: &str, fs: FsChoice) -> Result<()> {
+    let partition = udevutils::enumerate_partitions(loopback_name)?
+        .into_iter()
+        .next()
+        .ok_or(ErrorKind::Msg("no partition found after formatting".into()))?;
+
+    let devnode = partition.devnode.to_string_lossy();
+    let mut args: Vec<&str> = fs.mkfs_args().to_vec();
+    args.push(&devnode);
+    utils::run_check_output(fs.mkfs_cmd(), &args)
+        .chain_err(|| format!("failed to format volume as {:?}", fs))?;
+
+    if fs == FsChoice::Btrfs {
+        create_isos_subvolume(&partition.devnode)?;
+    }
+    Ok(())
+}
+
+// So the ISO-scanning path in mount_internal has somewhere to find ISOs on a
+// freshly formatted btrfs volume.
+fn create_isos_subvolume<P: AsRef<Path>>(device: P) -> Result<()> {
+    let tmp_mount = Path::new(FORMAT_MOUNT_ROOT);
+    fs::create_dir_all(tmp_mount)?;
+    utils::run_check_output("mount", &[device.as_ref(), tmp_mount])
+        .chain_err(|| "failed to mount freshly formatted btrfs volume")?;
+
+    let result = utils::run_check_output(
+        "btrfs",
+        &[
+            "subvolume",
+            "create",
+            &tmp_mount.join(ISO_SUBVOLUME).to_string_lossy(),
+        ],
+    );
+
+    utils::run_check_output("umount", &[tmp_mount])?;
+    result
+        .map(|_| ())
+        .chain_err(|| "failed to create ISOS subvolume")
+}
@@ -0,0 +1,177 @@
+use error::{ErrorKind, Result, ResultExt};
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum FsType {
+    Ext,
+    Exfat,
+    Ntfs,
+    Fat32,
+    Iso9660,
+}
+
+impl FsType {
+    pub fn mounter(&self) -> &'static str {
+        match *self {
+            FsType::Ext | FsType::Fat32 | FsType::Iso9660 => "mount",
+            FsType::Exfat => "mount.exfat",
+            FsType::Ntfs => "mount.ntfs-3g",
+        }
+    }
+
+    // Maps an ID_FS_TYPE value as reported by udev to our own FsType, so
+    // callers that already know the udev-detected type don't need to
+    // re-probe the superblock themselves.
+    pub fn from_udev_name(name: &str) -> Option<FsType> {
+        match name {
+            "ext2" | "ext3" | "ext4" => Some(FsType::Ext),
+            "exfat" => Some(FsType::Exfat),
+            "ntfs" => Some(FsType::Ntfs),
+            "vfat" => Some(FsType::Fat32),
+            "iso9660" => Some(FsType::Iso9660),
+            _ => None,
+        }
+    }
+}
+
+const EXT_MAGIC_OFFSET: u64 = 0x438;
+const EXT_MAGIC: [u8; 2] = [0x53, 0xEF];
+const EXT_LABEL_OFFSET: u64 = 1144;
+const EXT_LABEL_LEN: usize = 16;
+const EXFAT_SIG_OFFSET: u64 = 3;
+const EXFAT_SIG: &[u8] = b"EXFAT   ";
+const NTFS_SIG_OFFSET: u64 = 3;
+const NTFS_SIG: &[u8] = b"NTFS    ";
+const FAT32_SIG_OFFSET: u64 = 82;
+const FAT32_SIG: &[u8] = b"FAT32   ";
+const ISO9660_SIG_OFFSET: u64 = 32769;
+const ISO9660_SIG: &[u8] = b"CD001";
+
+fn read_at<P: AsRef<Path>>(device: P, offset: u64, len: usize) -> Result<Vec<u8>> {
+    let mut f = File::open(device.as_ref()).chain_err(|| "failed to open device for fs detection")?;
+    f.seek(SeekFrom::Start(offset))
+        .chain_err(|| "failed to seek device")?;
+    let mut buf = vec![0u8; len];
+    f.read_exact(&mut buf)
+        .chain_err(|| "failed to read device superblock")?;
+    Ok(buf)
+}
+
+// A volume smaller than a high-offset signature (e.g. ISO9660's at 32769)
+// simply can't carry that filesystem; treat the resulting short read as
+// "signature absent" rather than a hard error.
+fn matches_signature<P: AsRef<Path>>(device: P, offset: u64, signature: &[u8]) -> Result<bool> {
+    let mut f = File::open(device.as_ref()).chain_err(|| "failed to open device for fs detection")?;
+    f.seek(SeekFrom::Start(offset))
+        .chain_err(|| "failed to seek device")?;
+    let mut buf = vec![0u8; signature.len()];
+    match f.read_exact(&mut buf) {
+        Ok(()) => Ok(buf == signature),
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e).chain_err(|| "failed to read device superblock"),
+    }
+}
+
+// Identifies the filesystem on `device` by probing well-known superblock
+// signatures, rather than trying each mount helper in turn and keeping
+// whatever happens to succeed. Cheap, low-offset signatures are probed
+// first so a volume too small to hold a high-offset one (e.g. ISO9660's at
+// 32769) still gets correctly identified instead of erroring out early.
+pub fn detect_fs<P: AsRef<Path>>(device: P) -> Result<FsType> {
+    let device = device.as_ref();
+
+    if matches_signature(device, EXFAT_SIG_OFFSET, EXFAT_SIG)? {
+        return Ok(FsType::Exfat);
+    }
+    if matches_signature(device, NTFS_SIG_OFFSET, NTFS_SIG)? {
+        return Ok(FsType::Ntfs);
+    }
+    if matches_signature(device, FAT32_SIG_OFFSET, FAT32_SIG)? {
+        return Ok(FsType::Fat32);
+    }
+    if matches_signature(device, EXT_MAGIC_OFFSET, &EXT_MAGIC)? {
+        return Ok(FsType::Ext);
+    }
+    if matches_signature(device, ISO9660_SIG_OFFSET, ISO9660_SIG)? {
+        return Ok(FsType::Iso9660);
+    }
+
+    Err(ErrorKind::Msg(format!("Unrecognised filesystem on {}", device.display())).into())
+}
+
+// Only meaningful for FsType::Ext; reads the volume label pISO can use to
+// name the mount folder instead of a bare partition number.
+pub fn ext_label<P: AsRef<Path>>(device: P) -> Result<String> {
+    let raw = read_at(device, EXT_LABEL_OFFSET, EXT_LABEL_LEN)?;
+    let end = raw.iter().position(|&b| b == 0).unwrap_or(raw.len());
+    Ok(String::from_utf8_lossy(&raw[..end]).trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::io::Write;
+
+    // Writes a throwaway file at least `len` bytes long with `signature`
+    // placed at `offset`, so detect_fs can be exercised without a real block
+    // device.
+    fn fixture(name: &str, len: u64, offset: u64, signature: &[u8]) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "piso-fsdetect-test-{}-{}-{}",
+            std::process::id(),
+            name,
+            offset
+        ));
+        let mut f = File::create(&path).unwrap();
+        let total = len.max(offset + signature.len() as u64);
+        f.set_len(total).unwrap();
+        f.seek(SeekFrom::Start(offset)).unwrap();
+        f.write_all(signature).unwrap();
+        path
+    }
+
+    #[test]
+    fn detects_ext_on_a_small_volume() {
+        // Smaller than ISO9660's signature offset (32769), so this only
+        // passes if ext is probed before iso9660 or a short read there
+        // is treated as "signature absent" rather than an error.
+        let path = fixture("ext-small", 2048, EXT_MAGIC_OFFSET, &EXT_MAGIC);
+        assert_eq!(detect_fs(&path).unwrap(), FsType::Ext);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn detects_exfat_ntfs_fat32_and_iso9660() {
+        let cases: &[(u64, &[u8], FsType)] = &[
+            (EXFAT_SIG_OFFSET, EXFAT_SIG, FsType::Exfat),
+            (NTFS_SIG_OFFSET, NTFS_SIG, FsType::Ntfs),
+            (FAT32_SIG_OFFSET, FAT32_SIG, FsType::Fat32),
+            (ISO9660_SIG_OFFSET, ISO9660_SIG, FsType::Iso9660),
+        ];
+        for &(offset, signature, expected) in cases {
+            let path = fixture("sig", ISO9660_SIG_OFFSET + 8, offset, signature);
+            assert_eq!(detect_fs(&path).unwrap(), expected);
+            let _ = std::fs::remove_file(&path);
+        }
+    }
+
+    #[test]
+    fn errors_on_unrecognised_filesystem() {
+        let path = fixture("none", ISO9660_SIG_OFFSET + 8, 0, b"");
+        assert!(detect_fs(&path).is_err());
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn matches_signature_treats_short_read_as_absent() {
+        let path = fixture("tiny", 4, 0, b"");
+        assert_eq!(
+            matches_signature(&path, ISO9660_SIG_OFFSET, ISO9660_SIG).unwrap(),
+            false
+        );
+        let _ = std::fs::remove_file(&path);
+    }
+}
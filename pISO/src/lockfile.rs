@@ -0,0 +1,52 @@
+use error::{ErrorKind, Result, ResultExt};
+use std::fs::{self, File, OpenOptions};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+const LOCK_DIR: &str = "/var/run/piso/locks";
+
+const LOCK_EX: i32 = 2;
+const LOCK_NB: i32 = 4;
+const LOCK_UN: i32 = 8;
+
+extern "C" {
+    fn flock(fd: i32, operation: i32) -> i32;
+}
+
+// Held for the duration of a single mount-state transition. Dropping it
+// releases the advisory lock, so a panic or early return still lets the next
+// transition through instead of wedging the volume forever.
+pub struct VolumeLock {
+    file: File,
+}
+
+impl VolumeLock {
+    // Acquires an exclusive, non-blocking advisory lock on a per-volume lock
+    // file under LOCK_DIR, so a fast double Select or an overlapping on_load
+    // can't race two mount-state transitions against the same volume.
+    pub fn acquire(name: &str) -> Result<VolumeLock> {
+        fs::create_dir_all(LOCK_DIR)?;
+        let path = Path::new(LOCK_DIR).join(format!("{}.lock", name));
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .open(&path)
+            .chain_err(|| format!("failed to open lock file for {}", name))?;
+
+        let ret = unsafe { flock(file.as_raw_fd(), LOCK_EX | LOCK_NB) };
+        if ret != 0 {
+            return Err(ErrorKind::Msg(format!(
+                "volume {} is already mid mount-state transition",
+                name
+            )).into());
+        }
+
+        Ok(VolumeLock { file: file })
+    }
+}
+
+impl Drop for VolumeLock {
+    fn drop(&mut self) {
+        let _ = unsafe { flock(self.file.as_raw_fd(), LOCK_UN) };
+    }
+}
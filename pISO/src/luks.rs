@@ -0,0 +1,94 @@
+use error::{Result, ResultExt};
+use std::fs;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use utils;
+
+const KEY_DIR: &str = "/etc/piso/keys";
+const MAPPER_ROOT: &str = "/dev/mapper";
+
+// A raw random keyfile for cryptsetup, so pISO doesn't have to remember or
+// ask for a passphrase. This is not protected at rest: write_keyfile puts it
+// on the same plaintext disk cryptsetup reads it from, so anyone with access
+// to that disk can unlock the volume it guards. It's meant to stop casual
+// access to a pulled drive, not to resist an attacker with access to pISO's
+// own storage.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct KeyConfig {
+    pub key: Vec<u8>,
+}
+
+impl KeyConfig {
+    pub fn generate() -> Result<KeyConfig> {
+        Ok(KeyConfig { key: read_random(64)? })
+    }
+}
+
+fn read_random(n: usize) -> Result<Vec<u8>> {
+    let mut f = File::open("/dev/urandom").chain_err(|| "failed to open /dev/urandom")?;
+    let mut buf = vec![0u8; n];
+    f.read_exact(&mut buf)
+        .chain_err(|| "failed to read random bytes")?;
+    Ok(buf)
+}
+
+fn keyfile_path(name: &str) -> PathBuf {
+    Path::new(KEY_DIR).join(format!("{}.key", name))
+}
+
+fn write_keyfile(name: &str, key: &KeyConfig) -> Result<PathBuf> {
+    fs::create_dir_all(KEY_DIR)?;
+    let path = keyfile_path(name);
+    fs::write(&path, &key.key).chain_err(|| "failed to write luks keyfile")?;
+    Ok(path)
+}
+
+pub fn mapper_path(name: &str) -> PathBuf {
+    Path::new(MAPPER_ROOT).join(name)
+}
+
+// True if `name`'s LUKS container is already open, e.g. left behind by a
+// pISO restart. Callers use this to avoid luksOpen-ing a mapper that's
+// already there, which cryptsetup rejects with a hard error.
+pub fn is_open(name: &str) -> bool {
+    mapper_path(name).exists()
+}
+
+// One-time conversion of a plaintext volume into a LUKS container.
+pub fn format<P: AsRef<Path>>(device: P, name: &str, key: &KeyConfig) -> Result<()> {
+    let keyfile = write_keyfile(name, key)?;
+    utils::run_check_output(
+        "cryptsetup",
+        &[
+            "luksFormat",
+            "--batch-mode",
+            "--key-file",
+            &keyfile.to_string_lossy(),
+            &device.as_ref().to_string_lossy(),
+        ],
+    ).chain_err(|| "failed to luksFormat volume")?;
+    Ok(())
+}
+
+// Opens the LUKS container on `device`, producing /dev/mapper/<name>.
+pub fn open<P: AsRef<Path>>(device: P, name: &str, key: &KeyConfig) -> Result<PathBuf> {
+    let keyfile = write_keyfile(name, key)?;
+    utils::run_check_output(
+        "cryptsetup",
+        &[
+            "luksOpen",
+            "--key-file",
+            &keyfile.to_string_lossy(),
+            &device.as_ref().to_string_lossy(),
+            name,
+        ],
+    ).chain_err(|| "failed to luksOpen volume")?;
+    Ok(mapper_path(name))
+}
+
+pub fn close(name: &str) -> Result<()> {
+    utils::run_check_output("cryptsetup", &["luksClose", name])
+        .chain_err(|| "failed to luksClose volume")?;
+    Ok(())
+}
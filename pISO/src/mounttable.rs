@@ -0,0 +1,127 @@
+use error::{ErrorKind, Result, ResultExt};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// /proc/mounts octal-escapes space, tab, newline and backslash in paths
+// (e.g. "My Drive" becomes "My\040Drive"); the source field is unescaped
+// before it is stored, so is_source_mounted compares against the real path.
+fn octal_digit(b: u8) -> Option<u8> {
+    if b >= b'0' && b <= b'7' {
+        Some(b - b'0')
+    } else {
+        None
+    }
+}
+
+fn unescape_octal(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\' && i + 3 < bytes.len() {
+            let digits = (
+                octal_digit(bytes[i + 1]),
+                octal_digit(bytes[i + 2]),
+                octal_digit(bytes[i + 3]),
+            );
+            if let (Some(a), Some(b), Some(c)) = digits {
+                out.push(a * 64 + b * 8 + c);
+                i += 4;
+                continue;
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+// Only the source devnode is tracked: unmount_internal keys "already
+// mounted" off the devnode (see its comment for why), which is the only
+// thing any caller needs from /proc/mounts right now.
+pub struct MountTable {
+    sources: Vec<PathBuf>,
+}
+
+impl MountTable {
+    pub fn read() -> Result<MountTable> {
+        let contents =
+            fs::read_to_string("/proc/mounts").chain_err(|| "failed to read /proc/mounts")?;
+        MountTable::parse(&contents)
+    }
+
+    fn parse(contents: &str) -> Result<MountTable> {
+        let mut sources = vec![];
+        for line in contents.lines() {
+            let source = line.split_whitespace()
+                .next()
+                .ok_or(ErrorKind::Msg("malformed /proc/mounts line".into()))?;
+            sources.push(PathBuf::from(unescape_octal(source)));
+        }
+        Ok(MountTable { sources })
+    }
+
+    pub fn is_source_mounted<P: AsRef<Path>>(&self, source: P) -> bool {
+        self.sources.iter().any(|s| s == source.as_ref())
+    }
+}
+
+// Finds the loopback device (if any) already backing `backing_file`, by reading
+// /sys/block/loop*/loop/backing_file. This lets us recover from a pISO restart
+// without double-losetup'ing the same volume.
+pub fn find_loopback_for<P: AsRef<Path>>(backing_file: P) -> Result<Option<PathBuf>> {
+    let sys_block = Path::new("/sys/block");
+    if !sys_block.exists() {
+        return Ok(None);
+    }
+    for entry in fs::read_dir(sys_block)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if !name.starts_with("loop") {
+            continue;
+        }
+        let backing_path = entry.path().join("loop/backing_file");
+        if !backing_path.exists() {
+            continue;
+        }
+        let backing =
+            fs::read_to_string(&backing_path).chain_err(|| "failed to read loop backing_file")?;
+        if Path::new(backing.trim()) == backing_file.as_ref() {
+            return Ok(Some(Path::new("/dev").join(name)));
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{unescape_octal, MountTable};
+
+    #[test]
+    fn unescape_octal_leaves_plain_text_alone() {
+        assert_eq!(unescape_octal("/dev/loop0p1"), "/dev/loop0p1");
+    }
+
+    #[test]
+    fn unescape_octal_handles_space_tab_newline_and_backslash() {
+        assert_eq!(unescape_octal("My\\040Drive"), "My Drive");
+        assert_eq!(unescape_octal("a\\011b"), "a\tb");
+        assert_eq!(unescape_octal("a\\012b"), "a\nb");
+        assert_eq!(unescape_octal("a\\134b"), "a\\b");
+    }
+
+    #[test]
+    fn unescape_octal_ignores_trailing_backslash_without_enough_digits() {
+        assert_eq!(unescape_octal("trailing\\"), "trailing\\");
+        assert_eq!(unescape_octal("bad\\04"), "bad\\04");
+    }
+
+    #[test]
+    fn is_source_mounted_matches_unescaped_source() {
+        let table = MountTable::parse(
+            "/dev/loop0p1 /mnt/Some\\040Drive vfat rw,relatime 0 0\n",
+        ).unwrap();
+        assert!(table.is_source_mounted("/dev/loop0p1"));
+        assert!(!table.is_source_mounted("/dev/loop0p2"));
+    }
+}
@@ -0,0 +1,100 @@
+use error::{Result, ResultExt};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+use utils;
+
+const PARTITION_WAIT_TIMEOUT_SECS: u64 = 5;
+const PARTITION_POLL_INTERVAL_MILLIS: u64 = 100;
+
+pub struct PartitionInfo {
+    pub devnode: PathBuf,
+    pub fs_type: Option<String>,
+    pub fs_label: Option<String>,
+    pub part_number: String,
+}
+
+fn read_properties<P: AsRef<Path>>(devnode: P) -> Result<HashMap<String, String>> {
+    let devnode_str = devnode.as_ref().to_string_lossy();
+    let output = utils::run_check_output(
+        "udevadm",
+        &["info", "--query=property", "--name", &devnode_str],
+    ).chain_err(|| format!("failed to query udev properties for {}", devnode_str))?;
+
+    let mut properties = HashMap::new();
+    for line in output.lines() {
+        if let Some(idx) = line.find('=') {
+            properties.insert(line[..idx].to_string(), line[idx + 1..].to_string());
+        }
+    }
+    Ok(properties)
+}
+
+fn partition_names(sys_path: &Path, loopback_name: &str) -> Result<Vec<String>> {
+    if !sys_path.exists() {
+        return Ok(vec![]);
+    }
+    let mut names = vec![];
+    for entry in fs::read_dir(sys_path)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        // Partitions of a loopback device show up as children of its own
+        // /sys/class/block entry, named <loopback_name>p<N>.
+        if name != loopback_name && name.starts_with(loopback_name) {
+            names.push(name);
+        }
+    }
+    Ok(names)
+}
+
+// `udevadm settle` only drains udev's *current* event queue: if losetup -fP's
+// uevents haven't been enqueued yet, settle returns immediately with no
+// partitions present. Poll sysfs directly for the partition nodes to actually
+// show up (with a timeout, in case the image genuinely has none); the caller
+// then settles so their ID_FS_* properties have finished populating.
+fn wait_for_partitions(sys_path: &Path, loopback_name: &str) -> Result<()> {
+    let deadline = Instant::now() + Duration::from_secs(PARTITION_WAIT_TIMEOUT_SECS);
+    loop {
+        if !partition_names(sys_path, loopback_name)?.is_empty() {
+            return Ok(());
+        }
+        if Instant::now() >= deadline {
+            return Ok(());
+        }
+        thread::sleep(Duration::from_millis(PARTITION_POLL_INTERVAL_MILLIS));
+    }
+}
+
+// Enumerates the partitions of `loopback_name` (e.g. "loop0") the way udev
+// sees them, rather than racing the kernel by reading /dev once. This waits
+// for the partition nodes to actually appear and for udev to finish
+// processing them, then reads the filesystem type/label/partition number
+// from udev properties. The partition number falls back to the digits of
+// the device name when udev reports no PARTN.
+pub fn enumerate_partitions(loopback_name: &str) -> Result<Vec<PartitionInfo>> {
+    let sys_path = Path::new("/sys/class/block").join(loopback_name);
+    wait_for_partitions(&sys_path, loopback_name)?;
+
+    utils::run_check_output("udevadm", &["settle"])
+        .chain_err(|| "failed to wait for udev to settle")?;
+
+    let mut partitions = vec![];
+    for name in partition_names(&sys_path, loopback_name)? {
+        let devnode = Path::new("/dev").join(&name);
+        let properties = read_properties(&devnode)?;
+        let part_number = properties
+            .get("PARTN")
+            .cloned()
+            .unwrap_or_else(|| name.trim_start_matches(loopback_name).replace("p", ""));
+
+        partitions.push(PartitionInfo {
+            devnode: devnode,
+            fs_type: properties.get("ID_FS_TYPE").cloned(),
+            fs_label: properties.get("ID_FS_LABEL").cloned(),
+            part_number: part_number,
+        });
+    }
+    Ok(partitions)
+}
@@ -4,32 +4,55 @@ use config;
 use controller;
 use displaymanager::{DisplayManager, Position, Widget, Window, WindowId};
 use error::{ErrorKind, Result, ResultExt};
+use flash;
 use font;
+use format;
+use fsdetect;
 use input;
 use iso;
+use lockfile;
+use luks;
 use lvm;
+use mounttable;
+use udevutils;
 use usb;
 use utils;
 use render;
 use state;
+use std::cell::RefCell;
 use std::fs;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
 use std::sync::{Arc, Mutex};
 use std::thread;
 
 const VDRIVE_MOUNT_ROOT: &str = "/mnt";
 const ISO_FOLDER: &str = "ISOS";
 
+// A mounted partition, keeping the devnode alongside the mount point:
+// /proc/mounts records the source devnode without any of the escaping a
+// space-containing mount point folder name needs, so the devnode is what
+// unmount_internal must match "already mounted" against.
+pub struct PartitionMount {
+    pub devnode: PathBuf,
+    pub mount_point: PathBuf,
+}
+
 pub struct MountInfo {
     pub loopback_path: PathBuf,
-    pub part_mount_paths: Vec<PathBuf>,
+    pub partitions: Vec<PartitionMount>,
     pub isos: Vec<iso::Iso>,
+    // Set when this volume is encrypted; the loopback above is bound to the
+    // /dev/mapper node rather than directly to volume.path.
+    pub mapper_path: Option<PathBuf>,
 }
 
 pub enum MountState {
     Unmounted,
     Internal(MountInfo),
-    External(usb::StorageID),
+    // The mapper path is carried alongside the storage id so unmount_external
+    // can luksClose once the USB export is torn down.
+    External(usb::StorageID, Option<PathBuf>),
 }
 
 #[derive(Serialize, Deserialize)]
@@ -37,6 +60,9 @@ pub struct PersistVDriveState {
     pub external_mount: bool,
     pub readonly: bool,
     pub removable: bool,
+    pub encrypted: bool,
+    pub key_config: Option<luks::KeyConfig>,
+    pub fs: Option<format::FsChoice>,
 }
 
 impl Default for PersistVDriveState {
@@ -45,6 +71,9 @@ impl Default for PersistVDriveState {
             external_mount: false,
             readonly: false,
             removable: true,
+            encrypted: false,
+            key_config: None,
+            fs: None,
         }
     }
 }
@@ -56,6 +85,21 @@ pub struct VirtualDrive {
     pub window: WindowId,
     pub persist: PersistVDriveState,
     pub config: config::Config,
+    // Set while an Action::FlashImage write targeting this drive is running;
+    // not persisted, it only tracks in-process progress for rendering. Held
+    // in a RefCell so render(), which only gets &self, can still join the
+    // worker thread and clear this once it finishes.
+    active_flash: RefCell<Option<ActiveFlash>>,
+}
+
+// Tracks a single in-flight (or just-finished) flash worker thread, along
+// with the last result it produced so render() can show a failure (e.g. a
+// verification mismatch) instead of silently dropping it.
+struct ActiveFlash {
+    progress: Arc<flash::FlashProgress>,
+    total: u64,
+    join: Option<thread::JoinHandle<Result<()>>>,
+    last_error: Option<String>,
 }
 
 impl VirtualDrive {
@@ -73,6 +117,7 @@ impl VirtualDrive {
             volume: volume,
             persist: PersistVDriveState::default(),
             config: config.clone(),
+            active_flash: RefCell::new(None),
         })
     }
 
@@ -84,20 +129,79 @@ impl VirtualDrive {
         self.volume.size
     }
 
+    // For an encrypted volume, luksOpen's the backing device and returns the
+    // /dev/mapper node to actually mount or export, alongside its own path so
+    // the caller can luksClose it again on teardown. Plaintext volumes are
+    // passed through unchanged.
+    fn unlock(&self) -> Result<(PathBuf, Option<PathBuf>)> {
+        if self.persist.encrypted {
+            // pISO may have been killed mid-session, leaving this container
+            // already luksOpen'd; cryptsetup refuses to luksOpen a mapper
+            // name that already exists, so reuse it instead.
+            let mapper = if luks::is_open(self.name()) {
+                luks::mapper_path(self.name())
+            } else {
+                let key = self.persist
+                    .key_config
+                    .as_ref()
+                    .ok_or(ErrorKind::Msg("encrypted volume has no key configured".into()))?;
+                luks::open(&self.volume.path, self.name(), key)?
+            };
+            Ok((mapper.clone(), Some(mapper)))
+        } else {
+            Ok((self.volume.path.clone(), None))
+        }
+    }
+
+    // Joins the flash worker thread once it reports done, so a verification
+    // failure (or a panic) surfaces instead of being silently dropped along
+    // with the JoinHandle. A successful flash clears active_flash entirely;
+    // a failed one keeps it around so the error stays visible.
+    fn reap_active_flash(&self) {
+        let mut active_flash = self.active_flash.borrow_mut();
+        let done = match *active_flash {
+            Some(ref active) => active.join.is_some() && active.progress.done.load(Ordering::SeqCst),
+            None => false,
+        };
+        if !done {
+            return;
+        }
+        let active = active_flash.take().unwrap();
+        match active.join.unwrap().join() {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => {
+                *active_flash = Some(ActiveFlash {
+                    join: None,
+                    last_error: Some(e.to_string()),
+                    ..active
+                });
+            }
+            Err(_) => {
+                *active_flash = Some(ActiveFlash {
+                    join: None,
+                    last_error: Some("flash worker thread panicked".into()),
+                    ..active
+                });
+            }
+        }
+    }
+
     pub fn mount_external(&mut self) -> Result<()> {
+        let _lock = lockfile::VolumeLock::acquire(self.name())?;
         match self.state {
-            MountState::External(_) => Ok(()),
+            MountState::External(..) => Ok(()),
             MountState::Unmounted => {
+                let (export_path, mapper_path) = self.unlock()?;
                 let id = self.usb
                     .lock()?
                     .export_file(
-                        &self.volume.path,
+                        &export_path,
                         false,
                         self.persist.readonly,
                         self.persist.removable,
                     )
                     .chain_err(|| "failed to mount drive external")?;
-                self.state = MountState::External(id);
+                self.state = MountState::External(id, mapper_path);
                 self.persist.external_mount = true;
                 Ok(())
             }
@@ -108,16 +212,20 @@ impl VirtualDrive {
     }
 
     pub fn unmount_external(&mut self) -> Result<()> {
+        let _lock = lockfile::VolumeLock::acquire(self.name())?;
         match self.state {
             MountState::Unmounted => {}
             MountState::Internal(_) => {
                 return Err("Attempt to unmount_external while mounted internal".into());
             }
-            MountState::External(ref id) => {
+            MountState::External(ref id, ref mapper_path) => {
                 self.usb
                     .lock()?
                     .unexport_file(id)
                     .chain_err(|| "failed to unmount external")?;
+                if mapper_path.is_some() {
+                    luks::close(self.name())?;
+                }
             }
         }
         self.state = MountState::Unmounted;
@@ -129,101 +237,137 @@ impl VirtualDrive {
         match self.state {
             MountState::Unmounted => Ok(()),
             MountState::Internal(_) => self.unmount_internal(),
-            MountState::External(_) => self.unmount_external(),
+            MountState::External(..) => self.unmount_external(),
         }
     }
 
-    fn mount_partition<P1, P2>(&self, device: P1, target: P2) -> Result<()>
+    fn mount_partition<P1, P2>(
+        &self,
+        device: P1,
+        target: P2,
+        known_fstype: Option<fsdetect::FsType>,
+    ) -> Result<fsdetect::FsType>
     where
         P1: AsRef<Path>,
         P2: AsRef<Path>,
     {
-        let mounters = &["mount", "mount.exfat", "mount.ntfs-3g"];
-        for mounter in mounters {
-            let fsmount = utils::run_check_output(mounter, &[device.as_ref(), target.as_ref()]);
-            if fsmount.is_ok() {
-                return Ok(());
-            }
-        }
-        Err(format!(
-            "Failed to mount: {} to {}",
-            device.as_ref().display(),
-            target.as_ref().display()
-        ).into())
+        let fstype = match known_fstype {
+            Some(fstype) => fstype,
+            None => fsdetect::detect_fs(device.as_ref())
+                .chain_err(|| format!("Failed to mount: {}", device.as_ref().display()))?,
+        };
+        utils::run_check_output(fstype.mounter(), &[device.as_ref(), target.as_ref()])
+            .chain_err(|| {
+                format!(
+                    "Failed to mount: {} to {}",
+                    device.as_ref().display(),
+                    target.as_ref().display()
+                )
+            })?;
+        Ok(fstype)
     }
 
     pub fn mount_internal<'a, 'b>(
         &'a mut self,
         disp: &'b mut DisplayManager,
     ) -> Result<&'a MountInfo> {
+        let _lock = lockfile::VolumeLock::acquire(self.name())?;
         match self.state {
             MountState::Unmounted => {
-                let volume_path = &self.volume.path.to_string_lossy();
-                let loopback_path =
-                    PathBuf::from(utils::run_check_output("losetup", &["-f"])?.trim_right());
+                let (backing_path, mapper_path) = self.unlock()?;
+                let backing_path_str = backing_path.to_string_lossy();
+
+                // pISO may have been killed mid-session, leaving a loopback still bound
+                // to this volume. Reuse it instead of creating a second one.
+                let loopback_path = match mounttable::find_loopback_for(&backing_path)? {
+                    Some(existing) => existing,
+                    None => {
+                        let loopback_path = PathBuf::from(
+                            utils::run_check_output("losetup", &["-f"])?.trim_right(),
+                        );
+                        utils::run_check_output("losetup", &["-fP", &backing_path_str])?;
+                        loopback_path
+                    }
+                };
                 let loopback_name: String = loopback_path
                     .file_name()
                     .ok_or(ErrorKind::Msg("loopback path has no file name".into()))?
                     .to_string_lossy()
                     .into();
 
-                utils::run_check_output("losetup", &["-fP", volume_path])?;
-
+                let mount_table = mounttable::MountTable::read()?;
                 let mut mounted_partitions = vec![];
                 let mut isos = vec![];
-                for entry in fs::read_dir("/dev")? {
-                    let entry = entry?;
-                    if entry
-                        .file_name()
-                        .to_string_lossy()
-                        .starts_with(&loopback_name)
+                for partition in udevutils::enumerate_partitions(&loopback_name)? {
+                    let part_name = utils::translate_drive_name(&self.name(), &self.config);
+
+                    // Fall back to the fs we formatted this volume with if udev
+                    // hasn't reported an ID_FS_TYPE for the partition yet.
+                    let known_fstype = partition
+                        .fs_type
+                        .as_ref()
+                        .and_then(|name| fsdetect::FsType::from_udev_name(name))
+                        .or_else(|| self.persist.fs.and_then(|fs| fs.as_fstype()));
+
+                    // Prefer the udev-reported label to a bare partition number
+                    // when naming the mount folder; for ext udev sometimes
+                    // leaves ID_FS_LABEL empty even when the volume has one, so
+                    // fall back to reading it straight from the superblock.
+                    let udev_label = partition.fs_label.clone().filter(|l| !l.is_empty());
+                    let ext_label = if udev_label.is_none() && known_fstype == Some(fsdetect::FsType::Ext)
                     {
-                        let dev_name = entry.file_name().to_string_lossy().into_owned();
-                        // Skip the base loopback device
-                        if dev_name == loopback_name {
-                            continue;
-                        }
+                        fsdetect::ext_label(&partition.devnode).ok().filter(|l| !l.is_empty())
+                    } else {
+                        None
+                    };
+                    let mount_folder_name = match udev_label.or(ext_label) {
+                        Some(label) => format!("{} ({})", part_name, label),
+                        None => format!("{} (partition {})", part_name, partition.part_number),
+                    };
+
+                    let mount_point = Path::new(VDRIVE_MOUNT_ROOT).join(mount_folder_name);
+                    fs::create_dir_all(&mount_point)?;
 
-                        let part_num = dev_name.split("p").last().ok_or(ErrorKind::Msg(
-                            "Failed to determine partition number".into(),
-                        ))?;
-
-                        let part_name = utils::translate_drive_name(&self.name(), &self.config);
-                        let mount_folder_name = format!("{} (partition {})", part_name, part_num);
-
-                        let mount_point = Path::new(VDRIVE_MOUNT_ROOT).join(mount_folder_name);
-                        fs::create_dir_all(&mount_point)?;
-                        match self.mount_partition(&entry.path(), &mount_point) {
-                            Ok(_) => {
-                                mounted_partitions.push(mount_point.to_path_buf());
-
-                                let isopath = mount_point.join(ISO_FOLDER);
-                                if isopath.exists() {
-                                    for iso in fs::read_dir(isopath)? {
-                                        let iso = iso?;
-                                        if iso.file_name()
-                                            .into_string()
-                                            .map_err(|_| ErrorKind::Msg("Invalid file name".into()))?
-                                            .starts_with(".")
-                                        {
-                                            continue;
-                                        }
-                                        isos.push(iso::Iso::new(
-                                            disp,
-                                            self.usb.clone(),
-                                            iso.path(),
-                                        )?);
+                    // Already mounted from a previous pISO run: just rebuild our
+                    // bookkeeping instead of mounting over the top of it. Keyed off
+                    // the devnode, not the target, since /proc/mounts escapes the
+                    // target's spaces and mounttable only records sources.
+                    let mount_result = if mount_table.is_source_mounted(&partition.devnode) {
+                        Ok(())
+                    } else {
+                        self.mount_partition(&partition.devnode, &mount_point, known_fstype)
+                            .map(|_| ())
+                    };
+                    match mount_result {
+                        Ok(_) => {
+                            mounted_partitions.push(PartitionMount {
+                                devnode: partition.devnode.clone(),
+                                mount_point: mount_point.to_path_buf(),
+                            });
+
+                            let isopath = mount_point.join(ISO_FOLDER);
+                            if isopath.exists() {
+                                for iso in fs::read_dir(isopath)? {
+                                    let iso = iso?;
+                                    if iso.file_name()
+                                        .into_string()
+                                        .map_err(|_| ErrorKind::Msg("Invalid file name".into()))?
+                                        .starts_with(".")
+                                    {
+                                        continue;
                                     }
+                                    isos.push(iso::Iso::new(disp, self.usb.clone(), iso.path())?);
                                 }
                             }
-                            Err(e) => println!("An error occured while mounting: {}", e),
                         }
+                        Err(e) => println!("An error occured while mounting: {}", e),
                     }
                 }
                 self.state = MountState::Internal(MountInfo {
-                    part_mount_paths: mounted_partitions,
+                    partitions: mounted_partitions,
                     isos: isos,
                     loopback_path: loopback_path.to_path_buf(),
+                    mapper_path: mapper_path,
                 });
                 match &self.state {
                     &MountState::Internal(ref info) => Ok(info),
@@ -231,26 +375,52 @@ impl VirtualDrive {
                 }
             }
             MountState::Internal(ref state) => Ok(state),
-            MountState::External(_) => {
+            MountState::External(..) => {
                 Err("Attempt to mount_internal while mounted external".into())
             }
         }
     }
 
     pub fn unmount_internal(&mut self) -> Result<()> {
+        let _lock = lockfile::VolumeLock::acquire(self.name())?;
         match self.state {
             MountState::Unmounted => {}
             MountState::Internal(ref mut info) => {
                 for iso in info.isos.iter_mut() {
                     iso.unmount()?;
                 }
-                for part in info.part_mount_paths.iter() {
-                    utils::run_check_output("umount", &[&part])?;
-                    fs::remove_dir_all(&part)?;
+                let mut mount_table = mounttable::MountTable::read()?;
+                for part in info.partitions.iter() {
+                    // Key off the devnode, not the mount point: /proc/mounts
+                    // octal-escapes spaces in the target, and every mount folder
+                    // we create has one, so matching on the target would always
+                    // miss and leave us trying to rm -rf a live mountpoint.
+                    if mount_table.is_source_mounted(&part.devnode) {
+                        utils::run_check_output("umount", &[&part.mount_point])?;
+                        mount_table = mounttable::MountTable::read()?;
+                    }
+                    if mount_table.is_source_mounted(&part.devnode) {
+                        return Err(format!(
+                            "refusing to remove {}: still mounted after umount",
+                            part.mount_point.display()
+                        ).into());
+                    }
+                    if part.mount_point.exists() {
+                        fs::remove_dir_all(&part.mount_point)?;
+                    }
+                }
+                let backing_path = info.mapper_path.as_ref().unwrap_or(&self.volume.path);
+                if mounttable::find_loopback_for(backing_path)?.is_some() {
+                    utils::run_check_output(
+                        "losetup",
+                        &["-d", &info.loopback_path.to_string_lossy()],
+                    )?;
+                }
+                if info.mapper_path.is_some() {
+                    luks::close(self.name())?;
                 }
-                utils::run_check_output("losetup", &["-d", &info.loopback_path.to_string_lossy()])?;
             }
-            MountState::External(_) => {
+            MountState::External(..) => {
                 return Err("Attempt to unmount_internal while mounted external".into());
             }
         };
@@ -266,7 +436,7 @@ impl VirtualDrive {
                 self.unmount_internal()?;
                 self.mount_external()
             }
-            MountState::External(_) => {
+            MountState::External(..) => {
                 self.unmount_external()?;
                 self.mount_internal(disp)?;
                 Ok(())
@@ -283,10 +453,29 @@ impl render::Render for VirtualDrive {
         // Render the 'newname' from the config
         let render_name = utils::translate_drive_name(&self.name(), &self.config);
 
-        let label = format!("{} ({:.1}GB)", render_name, short_size);
+        self.reap_active_flash();
+        let label = match *self.active_flash.borrow() {
+            Some(ActiveFlash {
+                ref last_error,
+                ref progress,
+                total,
+                ..
+            }) => match *last_error {
+                Some(ref err) => format!("{} (flash failed: {})", render_name, err),
+                None => {
+                    let written = progress.written.load(Ordering::SeqCst) as u64;
+                    format!(
+                        "{} (flashing {}%)",
+                        render_name,
+                        (written * 100 / total.max(1)).min(100)
+                    )
+                }
+            },
+            None => format!("{} ({:.1}GB)", render_name, short_size),
+        };
         base.blit(&font::render_text(label), (12, 0));
         match self.state {
-            MountState::External(_) => {
+            MountState::External(..) => {
                 base.blit(&bitmap::Bitmap::from_slice(font::SQUARE), (6, 0));
             }
             _ => (),
@@ -326,6 +515,79 @@ impl input::Input for VirtualDrive {
                 self.persist.removable = !self.persist.removable;
                 Ok((true, vec![]))
             }
+            action::Action::ToggleDriveEncrypted(ref name) if name == self.name() => {
+                match self.state {
+                    MountState::Unmounted => {
+                        if self.persist.encrypted {
+                            // Clearing the flag without undoing luksFormat would make
+                            // unlock() hand the still-ciphertext volume straight to a
+                            // plaintext mount, silently losing access to the data.
+                            // There's no in-place LUKS-to-plaintext conversion here, so
+                            // refuse rather than pretend the volume became plaintext.
+                            Err(
+                                "Cannot disable encryption on an existing volume; reformat it instead"
+                                    .into(),
+                            )
+                        } else if fsdetect::detect_fs(&self.volume.path).is_ok() {
+                            // luksFormat overwrites the volume header and makes any
+                            // existing filesystem unreadable; only allow it on a
+                            // volume that doesn't already carry one.
+                            Err(
+                                "Cannot enable encryption on a volume with an existing filesystem; reformat it instead"
+                                    .into(),
+                            )
+                        } else {
+                            let key = luks::KeyConfig::generate()?;
+                            luks::format(&self.volume.path, self.name(), &key)?;
+                            self.persist.key_config = Some(key);
+                            self.persist.encrypted = true;
+                            Ok((true, vec![]))
+                        }
+                    }
+                    _ => Err("Attempt to toggle encryption while mounted".into()),
+                }
+            }
+            action::Action::FlashImage {
+                ref source,
+                ref targets,
+            } if targets.contains(&self.name().to_string()) =>
+            {
+                match self.state {
+                    MountState::Unmounted => {
+                        let total = fs::metadata(source).map(|m| m.len()).unwrap_or(0);
+                        let target = flash::FlashTarget {
+                            name: self.name().to_string(),
+                            path: self.volume.path.clone(),
+                        };
+                        let mut jobs = flash::flash_image(source.clone(), vec![target]);
+                        let (_, progress, join) = jobs.remove(0);
+                        *self.active_flash.borrow_mut() = Some(ActiveFlash {
+                            progress: progress,
+                            total: total,
+                            join: Some(join),
+                            last_error: None,
+                        });
+                        Ok((true, vec![]))
+                    }
+                    _ => Err("Attempt to flash a mounted drive".into()),
+                }
+            }
+            action::Action::CancelFlash(ref name) if name == self.name() => {
+                if let Some(ref active) = *self.active_flash.borrow() {
+                    active.progress.cancel.store(true, Ordering::SeqCst);
+                }
+                Ok((true, vec![]))
+            }
+            action::Action::FormatVDrive { ref name, fs } if name == self.name() => {
+                match self.state {
+                    MountState::Unmounted => {
+                        format::format_volume(&self.volume.path, fs)?;
+                        self.persist.fs = Some(fs);
+                        Ok((true, vec![]))
+                    }
+                    _ => Err("Attempt to format a mounted drive".into()),
+                }
+            }
             _ => Ok((false, vec![])),
         }
     }
@@ -355,7 +617,7 @@ impl state::Stateful for VirtualDrive {
             {
                 match self.state {
                     MountState::Internal(ref mount) => {
-                        for path in mount.part_mount_paths.iter().cloned() {
+                        for path in mount.partitions.iter().map(|p| p.mount_point.clone()) {
                             thread::spawn(move || {
                                 let _ = utils::run_check_output("fstrim", &[path]);
                             });